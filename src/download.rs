@@ -0,0 +1,70 @@
+//! Manifest fetching, abstracted over the HTTP client used to do it.
+//!
+//! Both backends honor the standard `HTTP_PROXY`/`HTTPS_PROXY`/`NO_PROXY` environment
+//! variables on their own (`reqwest` does so by default, `curl` natively), so there is
+//! nothing to wire up here beyond picking which one runs and how long it may take.
+
+use std::time::Duration;
+
+/// Which HTTP client to fetch manifests with.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Backend {
+    Reqwest,
+    Curl,
+}
+
+pub fn parse_backend(backend: &str) -> Result<Backend, &'static str> {
+    match backend {
+        "reqwest" => Ok(Backend::Reqwest),
+        "curl" => Ok(Backend::Curl),
+        _ => Err("Invalid backend, expected one of: reqwest, curl."),
+    }
+}
+
+/// Fetches manifests with a given backend, reusing one `reqwest::Client` (and its
+/// connection pool) across every call instead of paying for a fresh one each time.
+pub struct Downloader {
+    backend: Backend,
+    client: Option<reqwest::Client>,
+    timeout: Duration,
+}
+
+impl Downloader {
+    pub fn new(backend: Backend, timeout: Duration) -> Self {
+        let client = match backend {
+            Backend::Reqwest => reqwest::Client::builder().timeout(timeout).build().ok(),
+            Backend::Curl => None,
+        };
+
+        Downloader {
+            backend,
+            client,
+            timeout,
+        }
+    }
+
+    /// Fetches `url` as text, or `None` on any failure.
+    pub fn fetch(&self, url: &str) -> Option<String> {
+        match self.backend {
+            Backend::Reqwest => self.client.as_ref()?.get(url).send().ok()?.text().ok(),
+            Backend::Curl => fetch_curl(url, self.timeout),
+        }
+    }
+}
+
+fn fetch_curl(url: &str, timeout: Duration) -> Option<String> {
+    let output = std::process::Command::new("curl")
+        .arg("--silent")
+        .arg("--fail")
+        .arg("--max-time")
+        .arg(timeout.as_secs().to_string())
+        .arg(url)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout).ok()
+}
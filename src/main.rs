@@ -3,6 +3,7 @@
 #![allow(clippy::redundant_closure)]
 
 extern crate chrono;
+extern crate crossbeam;
 extern crate dirs;
 extern crate reqwest;
 extern crate structopt;
@@ -11,10 +12,15 @@ extern crate termcolor;
 use std::collections::HashSet;
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::Mutex;
 
 use structopt::{clap::AppSettings, StructOpt};
 use termcolor::{Color, ColorChoice, ColorSpec, WriteColor};
 
+mod download;
+
+use download::{Backend, Downloader};
+
 #[derive(StructOpt)]
 #[structopt(raw(global_settings = "&[
     AppSettings::DisableHelpSubcommand,
@@ -42,6 +48,31 @@ struct Args {
     #[structopt(short = "o", long = "offset", default_value = "0")]
     offset: usize,
 
+    /// Number of manifests to fetch concurrently.
+    #[structopt(short = "j", long = "jobs", default_value = "8")]
+    jobs: usize,
+
+    /// Binary-search the day window for the newest match instead of scanning it day by
+    /// day, assuming component availability only gets worse the more recent the date.
+    #[structopt(long = "bisect")]
+    bisect: bool,
+
+    /// Download backend to use for fetching manifests.
+    #[structopt(
+        long = "backend",
+        default_value = "reqwest",
+        parse(try_from_str = "download::parse_backend")
+    )]
+    backend: Backend,
+
+    /// Base URL to fetch manifests from, in place of the official dist server.
+    #[structopt(long = "mirror", default_value = "https://static.rust-lang.org/dist")]
+    mirror: String,
+
+    /// Timeout in seconds for each manifest download.
+    #[structopt(long = "timeout", default_value = "30")]
+    timeout: u64,
+
     /// Path to the Rustup binary.
     #[structopt(
         short = "b",
@@ -61,17 +92,23 @@ struct Args {
     rustup_dir: PathBuf,
 
     /// Target toolchain.
-    #[structopt(
-        short = "t",
-        long = "toolchain",
-        parse(try_from_str = "parse_toolchain")
-    )]
+    #[structopt(long = "toolchain", parse(try_from_str = "parse_toolchain"))]
     toolchain: Option<(String, String)>,
 
+    /// Comma or space-separated list of target triples that must be available for a
+    /// release to be considered valid. Defaults to the toolchain's own target when omitted.
+    #[structopt(short = "t", long = "target", use_delimiter = true)]
+    targets: Vec<String>,
+
     /// Space-separated list of components that must be available for a release to be considered valid.
     #[structopt(short = "c", long = "components")]
     components: Vec<String>,
 
+    /// Pre-populate the required components from a rustup-style profile
+    /// (`minimal`, `default` or `complete`) before component detection runs.
+    #[structopt(long = "profile", parse(try_from_str = "parse_profile"))]
+    profile: Option<Profile>,
+
     /// Space-separated list of components to be considered preview
     #[structopt(short = "p", long = "previews")]
     previews_vec: Vec<String>,
@@ -80,12 +117,16 @@ struct Args {
     #[structopt(short = "s", long = "skip-installed")]
     skip_components: bool,
 
+    /// Do not forward the validated components and targets to `rustup toolchain install`.
+    #[structopt(long = "no-install-components")]
+    no_install_components: bool,
+
     /// Command.
     #[structopt(subcommand)]
     command: Option<Cmd>,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, StructOpt)]
+#[derive(StructOpt)]
 enum Cmd {
     /// Find the latest available release that matches the current components.
     #[structopt(name = "find")]
@@ -103,26 +144,46 @@ enum Cmd {
         #[structopt(short = "k", long = "keep-previous")]
         keep_old: bool,
     },
+
+    /// Generate a shell completion script and print it to stdout.
+    #[structopt(name = "completions")]
+    Completions {
+        /// Shell to generate the completion script for.
+        shell: structopt::clap::Shell,
+    },
 }
 
 #[allow(clippy::cyclomatic_complexity)] // Allows us to have macros that use the parsed arguments.
 #[allow(clippy::write_literal)] // Necessary for the status! macro.
 fn main() {
     let Args {
+        backend,
+        bisect,
         command,
         components,
         previews_vec,
         days,
+        jobs,
+        mirror,
         no_colors,
+        no_install_components,
         offset,
+        profile,
         quiet,
         rustup_bin,
         mut rustup_dir,
         skip_components,
+        targets,
+        timeout,
         toolchain,
         verbose,
     } = Args::from_args();
 
+    if let Some(Cmd::Completions { ref shell }) = command {
+        Args::clap().gen_completions_to("rustup-find", shell.clone(), &mut std::io::stdout());
+        return;
+    }
+
     let previews: HashSet<_> = if !previews_vec.is_empty() {
         previews_vec.into_iter().collect()
     } else {
@@ -221,6 +282,16 @@ fn main() {
         );
     }
 
+    // Same as the bare `rustup!(...)` arm above, but for a dynamically-sized argument list.
+    macro_rules! rustup_args {
+        ($args: expr) => {
+            std::process::Command::new(&rustup_bin)
+                .args($args)
+                .output()
+                .expect("Failed to spawn rustup process.")
+        };
+    }
+
     // Find channel & target
     let (channel, target) = match toolchain {
         Some(values) => values,
@@ -236,9 +307,34 @@ fn main() {
 
     let toolchain = format!("{}-{}", channel, target);
 
+    let search_targets = if targets.is_empty() {
+        vec![target.clone()]
+    } else {
+        targets
+    };
+
     status!(info, "Channel: ", &channel, '.');
     status!(info, "Target: ", &target, '.');
 
+    if search_targets.len() > 1 {
+        status!(
+            info,
+            "Required targets: ",
+            {
+                use std::fmt::Write;
+
+                let mut s = search_targets[0].clone();
+
+                for t in &search_targets[1..] {
+                    let _ = write!(s, ", {}", t);
+                }
+
+                s
+            },
+            "."
+        );
+    }
+
     // Find needed components
     fn get_pair_from_component(component: String) -> (String, String) {
         let start = if component.starts_with("rust-") { 5 } else { 0 };
@@ -255,32 +351,47 @@ fn main() {
         .map(|component| get_pair_from_component(component))
         .collect();
 
-    if !skip_components {
+    if let Some(profile) = profile {
+        for component in profile_components(profile) {
+            components_set.insert(get_pair_from_component(component));
+        }
+    }
+
+    // `Complete` needs to know every component the manifest can offer, so it still
+    // needs this scan even when `--skip-installed` was passed.
+    if !skip_components || profile == Some(Profile::Complete) {
         let output = rustup!(output, "component", "list", "--toolchain", &toolchain);
 
         for line in output.lines() {
-            let component = if line.ends_with(" (default)") {
-                let line = &line[..line.len() - 10];
-
-                if line.ends_with(&target) {
-                    &line[..line.len() - target.len() - 1]
-                } else {
-                    line
-                }
+            let (component, is_installed_or_default) = if line.ends_with(" (default)") {
+                (&line[..line.len() - 10], true)
             } else if line.ends_with(" (installed)") {
-                let line = &line[..line.len() - 12];
+                (&line[..line.len() - 12], true)
+            } else {
+                (line, false)
+            };
 
-                if line.ends_with(&target) {
-                    &line[..line.len() - target.len() - 1]
-                } else {
-                    line
-                }
+            let component = if component.ends_with(&target) {
+                &component[..component.len() - target.len() - 1]
+            } else {
+                component
+            };
+
+            // `Complete` wants every component the manifest can offer, installed or
+            // not, regardless of `--skip-installed`. Otherwise an installed/default
+            // component is only relevant when we're not skipping them, and a plain
+            // available component is never relevant on its own.
+            let wanted = if profile == Some(Profile::Complete) {
+                true
+            } else if is_installed_or_default {
+                !skip_components
             } else {
-                ""
+                false
             };
 
             // Filter unwanted components
-            if !component.is_empty()
+            if wanted
+                && !component.is_empty()
                 && !component.starts_with("rust-src")
                 && !component.starts_with("rust-std")
             {
@@ -323,70 +434,172 @@ fn main() {
     }
 
     // Find latest version that matches the needed components
-    let mut date = chrono::Utc::now() - chrono::Duration::days(offset as i64 - 1);
-
+    let start_date = chrono::Utc::now() - chrono::Duration::days(offset as i64 - 1);
     let one_day = chrono::Duration::days(1);
-    let start_date = date;
 
-    let new_toolchain = 'main: loop {
-        date = date - one_day;
+    let date_str_at = |day_offset: usize| (start_date - one_day * day_offset as i32).format("%Y-%m-%d").to_string();
 
-        if start_date - date > chrono::Duration::days(days as _) {
-            fail!(5, "Could not find a match in the last ", days, " days.");
-        }
+    // Every fetched manifest is stashed here (each date is only ever fetched once per
+    // run, so this is never a cache hit) so we can recover the winning date's text
+    // afterwards without re-fetching it, to pull the rustc version out of it.
+    let manifest_cache: Mutex<std::collections::HashMap<String, Option<String>>> =
+        Mutex::new(std::collections::HashMap::new());
 
-        let date_str = date.format("%Y-%m-%d");
+    let downloader = Downloader::new(backend, std::time::Duration::from_secs(timeout));
+
+    let fetch_manifest = |date_str: &str| -> Option<String> {
         let url = format!(
-            "https://static.rust-lang.org/dist/{}/channel-rust-{}.toml",
-            date_str, channel
+            "{}/{}/channel-rust-{}.toml",
+            mirror, date_str, channel
         );
 
-        match reqwest::get(&url) {
-            Ok(mut res) => {
-                let text = match res.text() {
-                    Ok(text) => text,
-                    Err(_) => {
-                        status!(error, "Cannot get toolchain for ", date_str, ".");
-                        continue 'main;
-                    }
-                };
+        let text = downloader.fetch(&url);
 
-                match leftover_components(&previews, &target, &component_pairs, &text) {
-                    None => break format!("{}-{}-{}", channel, date_str, target),
-                    Some(leftovers) => {
-                        if !verbose {
-                            continue 'main;
-                        }
+        manifest_cache
+            .lock()
+            .unwrap()
+            .insert(date_str.to_string(), text.clone());
 
-                        if component_pairs.len() == leftovers.len() {
-                            status!(info, "No components were available in ", &date_str, ".");
-                            continue 'main;
-                        }
-                        if leftovers.len() == 1 {
-                            status!(
-                                info,
-                                "The following component was missing in ",
-                                &date_str,
-                                ": "
-                            );
-                        } else {
-                            status!(
-                                info,
-                                "The following components were missing in ",
-                                &date_str,
-                                ":"
-                            );
-                        }
-                        for component in &leftovers {
-                            status!(info, " - ", &(component), ".");
+        text
+    };
+
+    // Returns `None` once every required component is available for every requested
+    // target on that date, `Some(leftovers)` otherwise, or `Err(())` if the manifest
+    // itself could not be fetched.
+    let check_date = |day_offset: usize| -> Result<Option<Vec<(String, String)>>, ()> {
+        let date_str = date_str_at(day_offset);
+
+        match fetch_manifest(&date_str) {
+            None => {
+                status!(error, "Cannot get toolchain for ", &date_str, ".");
+                Err(())
+            }
+            Some(text) => Ok(leftover_components(
+                &previews,
+                &search_targets,
+                &component_pairs,
+                &text,
+            )
+            .err()),
+        }
+    };
+
+    let report_leftovers = |date_str: &str, leftovers: &[(String, String)]| {
+        if !verbose {
+            return;
+        }
+
+        if component_pairs.len() * search_targets.len() == leftovers.len() {
+            status!(info, "No components were available in ", date_str, ".");
+            return;
+        }
+        if leftovers.len() == 1 {
+            status!(
+                info,
+                "The following component was missing in ",
+                date_str,
+                ": "
+            );
+        } else {
+            status!(
+                info,
+                "The following components were missing in ",
+                date_str,
+                ":"
+            );
+        }
+        for (tgt, component) in leftovers {
+            status!(info, " - ", component, " for ", tgt, ".");
+        }
+    };
+
+    let found_offset = if bisect {
+        // Assumes component availability only improves the further back we look, so
+        // the window looks like a run of misses followed by a run of hits; binary
+        // search for the first (i.e. newest) hit instead of walking day by day.
+        let mut lo = 1;
+        let mut hi = days;
+        let mut found = None;
+
+        while lo <= hi {
+            let mid = lo + (hi - lo) / 2;
+
+            match check_date(mid) {
+                Ok(None) => {
+                    found = Some(mid);
+                    hi = mid - 1;
+                }
+                Ok(Some(leftovers)) => {
+                    report_leftovers(&date_str_at(mid), &leftovers);
+                    lo = mid + 1;
+                }
+                Err(()) => lo = mid + 1,
+            }
+        }
+
+        found
+    } else {
+        crossbeam::thread::scope(|scope| {
+            let next_offset = Mutex::new(1);
+            let best = Mutex::new(None);
+
+            let handles: Vec<_> = (0..jobs.max(1))
+                .map(|_| {
+                    scope.spawn(|_| loop {
+                        let offset = {
+                            let mut next = next_offset.lock().unwrap();
+
+                            if *next > days {
+                                break;
+                            }
+
+                            let offset = *next;
+                            *next += 1;
+                            offset
+                        };
+
+                        match check_date(offset) {
+                            Ok(None) => {
+                                let mut best = best.lock().unwrap();
+
+                                if best.map_or(true, |b| offset < b) {
+                                    *best = Some(offset);
+                                }
+                            }
+                            Ok(Some(leftovers)) => report_leftovers(&date_str_at(offset), &leftovers),
+                            Err(()) => (),
                         }
+                    })
+                })
+                .collect();
 
-                        continue 'main;
-                    }
-                };
+            for handle in handles {
+                let _ = handle.join();
             }
-            Err(_) => continue,
+
+            best.into_inner().unwrap()
+        })
+        .unwrap()
+    };
+
+    let (new_toolchain, new_manifest) = match found_offset {
+        Some(offset) => {
+            let date_str = date_str_at(offset);
+            let text = manifest_cache.lock().unwrap().get(&date_str).cloned().flatten();
+
+            (format!("{}-{}-{}", channel, date_str, target), text)
         }
+        None => fail!(5, "Could not find a match in the last ", days, " days."),
+    };
+
+    // Re-derive which literal component name (plain or `-preview`) actually satisfied
+    // the winning date, since `leftover_components` may have matched some of them only
+    // under their `-preview` alias; that's what has to be forwarded to `rustup`, not
+    // necessarily the name the user asked for.
+    let resolved_components = match &new_manifest {
+        Some(text) => leftover_components(&previews, &search_targets, &component_pairs, text)
+            .unwrap_or_else(|_| component_pairs.clone()),
+        None => component_pairs.clone(),
     };
 
     let command = match command {
@@ -401,9 +614,43 @@ fn main() {
 
     // Install toolchain
     status!(success, "Found valid toolchain: ", &new_toolchain, ".");
+
+    // Grab the previous toolchain's version before we possibly move or remove it below.
+    // There may not be one yet (e.g. a first-time `install`), so don't abort if this fails.
+    let old_rustc_version = {
+        let result = rustup!("run", &toolchain, "rustc", "--version");
+
+        if result.status.success() {
+            String::from_utf8(result.stdout)
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        } else {
+            "none".to_string()
+        }
+    };
+
     status!(info, "Installing toolchain...");
 
-    let output = rustup!("toolchain", "install", &new_toolchain);
+    let mut install_args = vec!["toolchain".to_string(), "install".to_string(), new_toolchain.clone()];
+
+    if !no_install_components {
+        for (name, arch) in &resolved_components {
+            install_args.push("-c".to_string());
+            install_args.push(if arch.is_empty() {
+                name.clone()
+            } else {
+                format!("{}-{}", name, arch)
+            });
+        }
+
+        for search_target in &search_targets {
+            install_args.push("-t".to_string());
+            install_args.push(search_target.clone());
+        }
+    }
+
+    let output = rustup_args!(&install_args);
 
     if !output.status.success() {
         status!(error, "Could not install toolchain ", &new_toolchain, ":");
@@ -415,6 +662,24 @@ fn main() {
 
     status!(success, "Installed toolchain ", &new_toolchain, ".");
 
+    let new_rustc_version = new_manifest
+        .as_ref()
+        .and_then(|text| manifest_rust_version(text))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let moved_forward = new_rustc_version != "unknown" && !old_rustc_version.contains(&new_rustc_version);
+
+    status!(
+        termcolor::StandardStream::stdout(colors),
+        if moved_forward { Color::Green } else { Color::White },
+        "[>] ",
+        "rustc: ",
+        &old_rustc_version,
+        " -> ",
+        &new_rustc_version,
+        "."
+    );
+
     if let Cmd::Replace { keep_old } = command {
         status!(info, "Replacing previous toolchain ", &toolchain, "...");
 
@@ -530,19 +795,82 @@ fn parse_path(path: &str) -> Result<PathBuf, &'static str> {
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Profile {
+    Minimal,
+    Default,
+    Complete,
+}
+
+fn parse_profile(profile: &str) -> Result<Profile, &'static str> {
+    match profile {
+        "minimal" => Ok(Profile::Minimal),
+        "default" => Ok(Profile::Default),
+        "complete" => Ok(Profile::Complete),
+        _ => Err("Invalid profile, expected one of: minimal, default, complete."),
+    }
+}
+
+/// Components required by a given rustup profile, mirroring `rustup set profile`.
+/// `Complete` is handled separately since it means "whatever the manifest offers".
+fn profile_components(profile: Profile) -> Vec<String> {
+    match profile {
+        Profile::Minimal => vec!["rustc", "cargo", "rust-std"],
+        Profile::Default => vec!["rustc", "cargo", "rust-std", "rust-docs", "rustfmt", "clippy"],
+        Profile::Complete => vec![],
+    }
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// Extracts the `version` field of the `[pkg.rust]` section from a channel manifest.
+fn manifest_rust_version(text: &str) -> Option<String> {
+    let mut in_pkg_rust = false;
+
+    for line in text.lines() {
+        if line == "[pkg.rust]" {
+            in_pkg_rust = true;
+            continue;
+        }
+
+        if !in_pkg_rust {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            break;
+        }
+
+        if line.starts_with("version = \"") && line.ends_with('"') {
+            return Some(line[11..line.len() - 1].to_string());
+        }
+    }
+
+    None
+}
+
+// On a full match, `previews.contains(name)` components may have been satisfied only
+// under their `-preview` alias, so the plain `component_pairs` names passed in are not
+// necessarily what rustup actually has available. Returns `Ok(resolved)` with one
+// (possibly `-preview`-renamed) pair per entry in `component_pairs`, in the same order,
+// so callers can forward the name that was truly validated instead of the requested one.
 fn leftover_components(
     previews: &HashSet<String>,
-    target: &str,
+    targets: &[String],
     component_pairs: &[(String, String)],
     text: &str,
-) -> Option<Vec<String>> {
+) -> Result<Vec<(String, String)>, Vec<(String, String)>> {
     let mut lines = text.lines();
-    let mut rem_comp: Vec<_> = component_pairs
+    let mut resolved = component_pairs.to_vec();
+    let mut rem_comp: Vec<_> = targets
         .iter()
-        .map(|(c, a)| {
-            let p = format!("[pkg.{}.target", &c);
-            let t = format!("{}-{}]", &a, &target);
-            (c.clone(), p, t)
+        .flat_map(|target| {
+            component_pairs.iter().enumerate().map(move |(idx, (c, a))| {
+                let p = format!("[pkg.{}.target", &c);
+                let t = format!("{}-{}]", &a, &target);
+                (target.clone(), idx, c.clone(), p, t)
+            })
         })
         .collect();
 
@@ -560,23 +888,27 @@ fn leftover_components(
         let mut i = 0;
 
         while i < rem_comp.len() {
-            let (c, p, t) = rem_comp[i].clone();
+            let (target, idx, c, p, t) = rem_comp[i].clone();
 
             if line.starts_with(&p) && line.ends_with(&t) {
                 rem_comp.swap_remove(i);
 
                 if rem_comp.is_empty() {
-                    return None;
+                    return Ok(resolved);
                 }
             } else if previews.contains(&c) {
                 let pre_c = format!("{}-preview", &c);
                 let pre_p = format!("[pkg.{}.target.", &pre_c);
-                rem_comp.push((pre_c, pre_p, t));
+                resolved[idx].0 = pre_c.clone();
+                rem_comp.push((target, idx, pre_c, pre_p, t));
                 rem_comp.swap_remove(i);
             } else {
                 i += 1;
             }
         }
     }
-    Some(rem_comp.into_iter().map(|r| r.0).collect())
+    Err(rem_comp
+        .into_iter()
+        .map(|(target, _, c, _, _)| (target, c))
+        .collect())
 }